@@ -0,0 +1,125 @@
+//! Loading of model configurations from their `config.json`.
+
+use candle_core::{bail, Error, Result};
+use candle_transformers::models::llama::{Config, LlamaConfig};
+use serde_json::Value;
+
+/// Config fields only present in models using multi-head latent attention,
+/// such as DeepSeek-V2 and V3.
+pub const MLA_CONFIG_FIELDS: [&str; 2] = ["kv_lora_rank", "q_lora_rank"];
+
+/// Checks that a raw `config.json` describes a model with standard
+/// multi-head attention.
+///
+/// Configs of architectures this crate can't serve, such as state space
+/// models, lack the attention fields and would otherwise fail to deserialize
+/// with an opaque missing field error. This names the architecture instead.
+/// Multi-head latent attention models are rejected too: their compressed KV
+/// doesn't fit the paged KV cache layout, yet their configs deserialize
+/// fine and would silently produce wrong outputs.
+pub fn check_supported_architecture(config: &Value) -> Result<()> {
+    if config.get("num_attention_heads").is_none() {
+        bail!(
+            "unsupported architecture {}: the config has no num_attention_heads, \
+             only attention-based models are supported",
+            architecture_name(config)
+        )
+    }
+    if let Some(field) = MLA_CONFIG_FIELDS
+        .iter()
+        .find(|field| config.get(**field).is_some_and(|value| !value.is_null()))
+    {
+        bail!(
+            "unsupported architecture {}: the config sets {field}, multi-head latent \
+             attention is not supported by the paged KV cache",
+            architecture_name(config)
+        )
+    }
+    Ok(())
+}
+
+/// Parses a Llama-style `config.json`, rejecting unsupported architectures
+/// with a descriptive error.
+pub fn parse_llama_config(raw_config: &str, use_flash_attn: bool) -> Result<Config> {
+    let config: Value = serde_json::from_str(raw_config).map_err(Error::wrap)?;
+    check_supported_architecture(&config)?;
+    let config: LlamaConfig = serde_json::from_value(config).map_err(Error::wrap)?;
+    Ok(config.into_config(use_flash_attn))
+}
+
+/// Describes the architecture of a config from its `architectures` or
+/// `model_type` fields.
+fn architecture_name(config: &Value) -> String {
+    if let Some(architectures) = config.get("architectures").and_then(Value::as_array) {
+        let names = architectures
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            return names.join(", ");
+        }
+    }
+    match config.get("model_type").and_then(Value::as_str) {
+        Some(model_type) => model_type.to_string(),
+        None => "<unknown>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LLAMA_CONFIG: &str = r#"{
+        "architectures": ["LlamaForCausalLM"],
+        "model_type": "llama",
+        "hidden_size": 64,
+        "intermediate_size": 128,
+        "vocab_size": 32,
+        "num_hidden_layers": 2,
+        "num_attention_heads": 4,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-5,
+        "max_position_embeddings": 2048
+    }"#;
+
+    #[test]
+    fn test_mamba_config_is_rejected() -> Result<()> {
+        let mamba_config = r#"{
+            "architectures": ["MambaForCausalLM"],
+            "model_type": "mamba",
+            "hidden_size": 768,
+            "state_size": 16,
+            "num_hidden_layers": 24,
+            "vocab_size": 50280
+        }"#;
+        let err = parse_llama_config(mamba_config, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("unsupported architecture MambaForCausalLM"),
+            "{err}"
+        );
+
+        let config = parse_llama_config(LLAMA_CONFIG, false)?;
+        assert_eq!(config.num_attention_heads, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deepseek_mla_config_is_rejected() -> Result<()> {
+        let mut config: Value = serde_json::from_str(LLAMA_CONFIG).map_err(Error::wrap)?;
+        config["architectures"] = serde_json::json!(["DeepseekV2ForCausalLM"]);
+        config["kv_lora_rank"] = serde_json::json!(512);
+        let err = parse_llama_config(&config.to_string(), false).unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "unsupported architecture DeepseekV2ForCausalLM: the config sets kv_lora_rank"
+            ),
+            "{err}"
+        );
+
+        // A null field, as some exported configs have, doesn't enable MLA
+        config["kv_lora_rank"] = Value::Null;
+        parse_llama_config(&config.to_string(), false)?;
+        Ok(())
+    }
+}
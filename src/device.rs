@@ -0,0 +1,44 @@
+//! Checks of a device's capabilities against the requested compute dtype.
+
+use candle_core::{bail, DType, Result};
+
+/// Lowest CUDA compute capability, as `(major, minor)`, with native bf16
+/// support (sm80, Ampere).
+pub const MIN_BF16_COMPUTE_CAPABILITY: (u32, u32) = (8, 0);
+
+/// Checks that a CUDA device of the given `(major, minor)` compute capability
+/// can run a model in `dtype`.
+///
+/// bf16 kernels require sm80 or newer; on older GPUs they either fail to
+/// launch or silently misbehave, so this errors and suggests f16 instead.
+/// The capability is passed in rather than queried: nothing in this crate
+/// detects it when a model is loaded, so the caller has to query the device
+/// and run this check itself before loading weights in `dtype`.
+pub fn check_dtype_support(dtype: DType, compute_capability: (u32, u32)) -> Result<()> {
+    if dtype == DType::BF16 && compute_capability < MIN_BF16_COMPUTE_CAPABILITY {
+        let (major, minor) = compute_capability;
+        bail!(
+            "bf16 requires a GPU with compute capability {}.{} or newer, but the device is sm{major}{minor}; use f16 instead",
+            MIN_BF16_COMPUTE_CAPABILITY.0,
+            MIN_BF16_COMPUTE_CAPABILITY.1
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bf16_rejected_on_sm75() -> Result<()> {
+        let sm75 = (7, 5);
+        let err = check_dtype_support(DType::BF16, sm75).unwrap_err();
+        assert!(err.to_string().contains("device is sm75"), "{err}");
+        assert!(err.to_string().contains("use f16 instead"), "{err}");
+        check_dtype_support(DType::F16, sm75)?;
+        check_dtype_support(DType::BF16, MIN_BF16_COMPUTE_CAPABILITY)?;
+        check_dtype_support(DType::BF16, (9, 0))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+//! Helpers for decode loops: stop conditions, sampling settings and
+//! post-processing of the logits of each step.
+
+use candle_core::{bail, DType, Result, Tensor, D};
+use candle_nn::ops::log_softmax;
+use candle_transformers::{
+    generation::{LogitsProcessor, Sampling},
+    models::llama::{Config, LlamaEosToks},
+    utils::apply_repeat_penalty,
+};
+use tokenizers::Tokenizer;
+
+/// Token used as end of sequence when the model config does not specify one.
+pub const DEFAULT_EOS_TOKEN: &str = "</s>";
+
+/// Resolves every token id that should stop generation.
+///
+/// The ids listed in `config.eos_token_id` take precedence, whether a single
+/// id or a list (as in Llama 3). Otherwise, falls back to the tokenizer's
+/// [`DEFAULT_EOS_TOKEN`]. The returned vector is empty if neither source
+/// provides a stop token, in which case generation only ends on length.
+pub fn resolve_eos_tokens(config: &Config, tokenizer: &Tokenizer) -> Vec<u32> {
+    match &config.eos_token_id {
+        Some(LlamaEosToks::Single(eos_token_id)) => vec![*eos_token_id],
+        Some(LlamaEosToks::Multiple(eos_token_ids)) => eos_token_ids.clone(),
+        None => tokenizer
+            .token_to_id(DEFAULT_EOS_TOKEN)
+            .into_iter()
+            .collect(),
+    }
+}
+
+/// Tracks which sequences of a batch are still decoding.
+///
+/// Each sequence has its own maximum number of generated tokens, and is
+/// retired as soon as it reaches it or samples one of the EOS tokens, while
+/// the rest of the batch keeps going.
+#[derive(Clone, Debug)]
+pub struct ActiveSequences {
+    max_tokens: Vec<usize>,
+    num_generated_tokens: Vec<usize>,
+    finished: Vec<bool>,
+    eos_tokens: Vec<u32>,
+}
+
+impl ActiveSequences {
+    /// Creates a tracker for a batch where sequence `i` generates at most
+    /// `max_tokens[i]` tokens. Sequences with a limit of 0 start retired.
+    pub fn new(max_tokens: Vec<usize>, eos_tokens: Vec<u32>) -> Self {
+        let finished = max_tokens.iter().map(|max| *max == 0).collect();
+        Self {
+            num_generated_tokens: vec![0; max_tokens.len()],
+            max_tokens,
+            finished,
+            eos_tokens,
+        }
+    }
+
+    /// Batch indices of the sequences still decoding, in increasing order.
+    pub fn active_indices(&self) -> Vec<usize> {
+        (0..self.finished.len())
+            .filter(|i| !self.finished[*i])
+            .collect()
+    }
+
+    /// Returns true once every sequence has been retired.
+    pub fn is_finished(&self) -> bool {
+        self.finished.iter().all(|finished| *finished)
+    }
+
+    /// Number of tokens generated so far by each sequence.
+    pub fn num_generated_tokens(&self) -> &[usize] {
+        &self.num_generated_tokens
+    }
+
+    /// Records one decoding step, where `tokens[j]` was sampled for the `j`-th
+    /// entry of [`Self::active_indices`]. Returns the batch indices of the
+    /// sequences retired by this step.
+    pub fn record(&mut self, tokens: &[u32]) -> Result<Vec<usize>> {
+        let active_indices = self.active_indices();
+        if tokens.len() != active_indices.len() {
+            bail!(
+                "got {} tokens for {} active sequences",
+                tokens.len(),
+                active_indices.len()
+            )
+        }
+        let mut retired = vec![];
+        for (i, token) in active_indices.into_iter().zip(tokens) {
+            self.num_generated_tokens[i] += 1;
+            if self.eos_tokens.contains(token) || self.num_generated_tokens[i] >= self.max_tokens[i]
+            {
+                self.finished[i] = true;
+                retired.push(i);
+            }
+        }
+        Ok(retired)
+    }
+}
+
+/// Log-probability of a sampled token, together with the most likely
+/// alternatives at the same position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenLogprobs {
+    /// The sampled token id
+    pub token: u32,
+    /// Log-probability of `token`
+    pub logprob: f32,
+    /// The `top_n` most likely `(token, logprob)` pairs, in decreasing order.
+    /// Tokens with a logprob of `-inf` are never listed, so this holds fewer
+    /// than `top_n` entries when fewer tokens can be sampled.
+    pub top_logprobs: Vec<(u32, f32)>,
+}
+
+/// Computes the log-probability of `token` and the `top_n` most likely tokens
+/// from a `[vocab_size]` logits tensor.
+///
+/// The log-softmax and the search for the `top_n` alternatives run in f32 on
+/// the logits' device, and only the requested values are copied back to the
+/// host at the end, so the full distribution never leaves the device.
+pub fn token_logprobs(logits: &Tensor, token: u32, top_n: usize) -> Result<TokenLogprobs> {
+    let vocab_size = logits.dims1()?;
+    if token as usize >= vocab_size {
+        bail!("token {token} is out of range for a vocabulary of size {vocab_size}")
+    }
+    let device = logits.device();
+    let logprobs = log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+    let mut values = vec![logprobs.narrow(0, token as usize, 1)?.copy()?];
+    let mut tokens = vec![];
+
+    // Repeated argmax is cheaper than a full sort for the handful of
+    // alternatives usually requested, and avoids the sort kernel's shared
+    // memory limit on large vocabularies. Each pick is masked in place, so
+    // nothing is copied back until all of them are known.
+    let masked = Tensor::full(f32::NEG_INFINITY, 1, device)?;
+    for _ in 0..top_n.min(vocab_size) {
+        let best = logprobs.argmax_keepdim(D::Minus1)?;
+        values.push(logprobs.gather(&best, D::Minus1)?);
+        logprobs.scatter_set(&best, &masked, D::Minus1)?;
+        tokens.push(best);
+    }
+
+    let values = Tensor::cat(&values, 0)?.to_vec1::<f32>()?;
+    let tokens = if tokens.is_empty() {
+        vec![]
+    } else {
+        Tensor::cat(&tokens, 0)?.to_vec1::<u32>()?
+    };
+    let top_logprobs = tokens
+        .into_iter()
+        .zip(values[1..].iter().copied())
+        // Only masked tokens remain, e.g. after top-k or n-gram masking
+        .take_while(|(_, logprob)| *logprob != f32::NEG_INFINITY)
+        .collect();
+
+    Ok(TokenLogprobs {
+        token,
+        logprob: values[0],
+        top_logprobs,
+    })
+}
+
+/// Returns the tokens that would complete an n-gram of size `ngram_size`
+/// already present in `tokens`, the generated token history.
+///
+/// The current prefix is the last `ngram_size - 1` tokens of the history;
+/// every earlier occurrence of that prefix bans the token that followed it.
+/// An `ngram_size` of 0 disables the check.
+pub fn banned_ngram_tokens(tokens: &[u32], ngram_size: usize) -> Vec<u32> {
+    if ngram_size == 0 || tokens.len() + 1 < ngram_size {
+        return vec![];
+    }
+    let prefix = &tokens[tokens.len() + 1 - ngram_size..];
+    let mut banned = tokens
+        .windows(ngram_size)
+        .filter(|ngram| &ngram[..ngram_size - 1] == prefix)
+        .map(|ngram| ngram[ngram_size - 1])
+        .collect::<Vec<_>>();
+    banned.sort_unstable();
+    banned.dedup();
+    banned
+}
+
+/// Sets the `[vocab_size]` logits of every token that would repeat an n-gram
+/// of size `ngram_size` from `tokens` to `-inf`, so it can't be sampled.
+///
+/// Only the banned token ids are uploaded, and they are scattered into the
+/// logits on their device.
+pub fn apply_no_repeat_ngram(logits: &Tensor, tokens: &[u32], ngram_size: usize) -> Result<Tensor> {
+    let banned = banned_ngram_tokens(tokens, ngram_size);
+    if banned.is_empty() {
+        return Ok(logits.clone());
+    }
+    let vocab_size = logits.dims1()?;
+    if let Some(token) = banned.iter().find(|token| **token as usize >= vocab_size) {
+        bail!("token {token} is out of range for a vocabulary of size {vocab_size}")
+    }
+    let num_banned = banned.len();
+    let banned = Tensor::from_vec(banned, num_banned, logits.device())?;
+    let masked =
+        Tensor::full(f32::NEG_INFINITY, num_banned, logits.device())?.to_dtype(logits.dtype())?;
+    logits.scatter(&banned, &masked, D::Minus1)
+}
+
+/// Per-request sampling settings.
+///
+/// Each sequence in a batch builds its own [`LogitsProcessor`] from its
+/// parameters, so requests batched together can sample differently.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplingParams {
+    /// Softmax temperature, greedy decoding is used if `None` or close to 0
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability cutoff
+    pub top_p: Option<f64>,
+    /// Only sample among the `top_k` most likely tokens
+    pub top_k: Option<usize>,
+    /// Seed of the sampling random number generator
+    pub seed: u64,
+    /// Penalty applied to tokens that were recently generated, 1.0 disables it
+    pub repetition_penalty: f32,
+    /// Number of trailing tokens the repetition penalty looks at
+    pub repeat_last_n: usize,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: None,
+            top_p: None,
+            top_k: None,
+            seed: 0,
+            repetition_penalty: 1.0,
+            repeat_last_n: 64,
+        }
+    }
+}
+
+impl SamplingParams {
+    /// Builds the [`LogitsProcessor`] sampling according to these parameters.
+    pub fn logits_processor(&self) -> LogitsProcessor {
+        let sampling = match self.temperature {
+            Some(temperature) if temperature >= 1e-7 => match (self.top_k, self.top_p) {
+                (None, None) => Sampling::All { temperature },
+                (Some(k), None) => Sampling::TopK { k, temperature },
+                (None, Some(p)) => Sampling::TopP { p, temperature },
+                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            },
+            _ => Sampling::ArgMax,
+        };
+        LogitsProcessor::from_sampling(self.seed, sampling)
+    }
+
+    /// Applies the repetition penalty to `logits` given the sequence's tokens
+    /// so far. The logits are returned unchanged if the penalty is disabled.
+    pub fn apply_repetition_penalty(&self, logits: &Tensor, tokens: &[u32]) -> Result<Tensor> {
+        if self.repetition_penalty == 1.0 {
+            return Ok(logits.clone());
+        }
+        let start = tokens.len().saturating_sub(self.repeat_last_n);
+        apply_repeat_penalty(logits, self.repetition_penalty, &tokens[start..])
+    }
+}
+
+/// Checks that `logits`, of shape `[vocab_size]` or `[batch_size, vocab_size]`,
+/// contain no NaN or `+inf` values, returning an error naming the first
+/// failing batch index otherwise.
+///
+/// `-inf` is allowed, as it is how masked tokens are excluded from sampling,
+/// e.g. by [`apply_no_repeat_ngram`], so the check can run after masking.
+///
+/// The scan runs on the logits' device and only one flag per batch entry is
+/// copied back. It is meant as an opt-in debugging aid, to surface numerical
+/// blowups before sampling turns them into garbage tokens.
+pub fn check_finite_logits(logits: &Tensor) -> Result<()> {
+    let logits = match logits.rank() {
+        1 => logits.unsqueeze(0)?,
+        2 => logits.clone(),
+        rank => bail!("expected logits of rank 1 or 2, got rank {rank}"),
+    };
+    let is_nan = logits.ne(&logits)?;
+    let is_inf = logits.eq(f64::INFINITY)?;
+    let invalid = is_nan.maximum(&is_inf)?.max(D::Minus1)?.to_vec1::<u8>()?;
+    if let Some(batch_index) = invalid.iter().position(|invalid| *invalid != 0) {
+        bail!("logits for batch index {batch_index} contain NaN or +inf values")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use candle_core::Device;
+    use candle_transformers::models::llama::Config;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let vocab = HashMap::from([
+            ("<unk>".to_string(), 0),
+            ("<s>".to_string(), 1),
+            (DEFAULT_EOS_TOKEN.to_string(), 2),
+        ]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("<unk>".to_string())
+            .build()
+            .unwrap();
+        Tokenizer::new(model)
+    }
+
+    #[test]
+    fn test_resolve_eos_tokens() {
+        let tokenizer = tokenizer();
+        let mut config = Config::config_7b_v2(false);
+
+        config.eos_token_id = Some(LlamaEosToks::Single(7));
+        assert_eq!(resolve_eos_tokens(&config, &tokenizer), vec![7]);
+
+        config.eos_token_id = Some(LlamaEosToks::Multiple(vec![7, 9]));
+        assert_eq!(resolve_eos_tokens(&config, &tokenizer), vec![7, 9]);
+
+        config.eos_token_id = None;
+        assert_eq!(resolve_eos_tokens(&config, &tokenizer), vec![2]);
+    }
+
+    #[test]
+    fn test_active_sequences_max_tokens() -> Result<()> {
+        let eos_token = 2;
+        let mut sequences = ActiveSequences::new(vec![2, 4], vec![eos_token]);
+
+        assert!(sequences.record(&[5, 6])?.is_empty());
+        assert_eq!(sequences.record(&[5, 6])?, [0]);
+        assert_eq!(sequences.active_indices(), [1]);
+        assert!(sequences.record(&[5, 6]).is_err());
+        assert!(sequences.record(&[6])?.is_empty());
+        assert_eq!(sequences.record(&[6])?, [1]);
+
+        assert!(sequences.is_finished());
+        assert_eq!(sequences.num_generated_tokens(), [2, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_active_sequences_multiple_eos_tokens() -> Result<()> {
+        let mut config = Config::config_7b_v2(false);
+        config.eos_token_id = Some(LlamaEosToks::Multiple(vec![7, 9]));
+        let eos_tokens = resolve_eos_tokens(&config, &tokenizer());
+        let mut sequences = ActiveSequences::new(vec![10; 3], eos_tokens);
+
+        // The default eos token is not one of the configured ones
+        assert!(sequences.record(&[2, 5, 5])?.is_empty());
+        assert_eq!(sequences.record(&[9, 5, 5])?, [0]);
+        assert_eq!(sequences.record(&[7, 9])?, [1, 2]);
+
+        assert!(sequences.is_finished());
+        assert_eq!(sequences.num_generated_tokens(), [2, 3, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_repeat_ngram() -> Result<()> {
+        let vocab_size = 5;
+        // Logits that greedily cycle through tokens 0, 1, 2
+        let cycling_logits = |last: u32| {
+            let logits = (0..vocab_size as u32)
+                .map(|token| if token == (last + 1) % 3 { 1f32 } else { 0. })
+                .collect::<Vec<_>>();
+            Tensor::new(logits, &Device::Cpu)
+        };
+
+        let mut tokens = vec![0];
+        for _ in 0..12 {
+            let logits = cycling_logits(*tokens.last().unwrap())?;
+            let logits = apply_no_repeat_ngram(&logits, &tokens, 2)?;
+            tokens.push(logits.argmax(D::Minus1)?.to_scalar::<u32>()?);
+        }
+
+        let mut bigrams = tokens.windows(2).collect::<Vec<_>>();
+        let num_bigrams = bigrams.len();
+        bigrams.sort();
+        bigrams.dedup();
+        assert_eq!(bigrams.len(), num_bigrams, "repeated bigram in {tokens:?}");
+
+        // Only the banned tokens are masked, in the logits' own dtype
+        let logits = Tensor::new(&[0.5f32, 1., 2., 3.], &Device::Cpu)?.to_dtype(DType::BF16)?;
+        let masked = apply_no_repeat_ngram(&logits, &[1, 3, 1, 2, 1], 2)?;
+        assert_eq!(masked.dtype(), DType::BF16);
+        assert_eq!(
+            masked.to_dtype(DType::F32)?.to_vec1::<f32>()?,
+            [0.5, 1., f32::NEG_INFINITY, f32::NEG_INFINITY]
+        );
+        assert!(apply_no_repeat_ngram(&logits, &[1, 7, 1], 2).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_banned_ngram_tokens() {
+        assert_eq!(banned_ngram_tokens(&[1, 2, 3, 1], 2), vec![2]);
+        assert_eq!(banned_ngram_tokens(&[1, 2, 3, 1, 2], 3), vec![3]);
+        assert!(banned_ngram_tokens(&[1, 2, 1], 0).is_empty());
+    }
+
+    #[test]
+    fn test_sampling_params_per_sequence() -> Result<()> {
+        let logits = Tensor::new(&[1f32, 0.9, 0.8, 0.7], &Device::Cpu)?;
+        let cold = SamplingParams {
+            temperature: Some(0.01),
+            seed: 42,
+            ..Default::default()
+        };
+        let hot = SamplingParams {
+            temperature: Some(5.0),
+            seed: 42,
+            ..Default::default()
+        };
+        let mut processors = [cold.logits_processor(), hot.logits_processor()];
+
+        let mut samples = [vec![], vec![]];
+        for _ in 0..32 {
+            for (processor, samples) in processors.iter_mut().zip(samples.iter_mut()) {
+                samples.push(processor.sample(&logits)?);
+            }
+        }
+        assert!(samples[0].iter().all(|token| *token == 0));
+        assert!(samples[1].iter().any(|token| *token != 0));
+        assert_ne!(samples[0], samples[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_logprobs() -> Result<()> {
+        let values = [1f32, 3., 2., 0.5];
+        let logits = Tensor::new(&values, &Device::Cpu)?;
+        let token = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+        let logprobs = token_logprobs(&logits, token, 2)?;
+
+        let log_sum_exp = values.iter().map(|value| value.exp()).sum::<f32>().ln();
+        let expected = values[token as usize] - log_sum_exp;
+        assert_eq!(token, 1);
+        assert!((logprobs.logprob - expected).abs() < 1e-6);
+        assert_eq!(logprobs.top_logprobs.len(), 2);
+        assert_eq!(logprobs.top_logprobs[0], (1, logprobs.logprob));
+        assert_eq!(logprobs.top_logprobs[1].0, 2);
+        assert!((logprobs.top_logprobs[1].1 - (values[2] - log_sum_exp)).abs() < 1e-6);
+
+        assert!(token_logprobs(&logits, token, 0)?.top_logprobs.is_empty());
+        let all_tokens = token_logprobs(&logits, 3, 10)?;
+        assert!((all_tokens.logprob - (values[3] - log_sum_exp)).abs() < 1e-6);
+        let all_tokens = all_tokens
+            .top_logprobs
+            .iter()
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        assert_eq!(all_tokens, [1, 2, 0, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_token_logprobs_skips_masked_tokens() -> Result<()> {
+        let logits = Tensor::new(
+            &[1f32, f32::NEG_INFINITY, f32::NEG_INFINITY, 0.5],
+            &Device::Cpu,
+        )?;
+        let logprobs = token_logprobs(&logits, 0, 4)?;
+        let top_tokens = logprobs
+            .top_logprobs
+            .iter()
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+        assert_eq!(top_tokens, vec![0, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_finite_logits() -> Result<()> {
+        let device = Device::Cpu;
+        let logits = Tensor::new(&[[0.5f32, 1., 2.], [1., 0.5, 0.]], &device)?;
+        check_finite_logits(&logits)?;
+        check_finite_logits(&logits.get(0)?)?;
+
+        // Masking a repeated bigram leaves -inf, which is allowed
+        let masked = apply_no_repeat_ngram(&logits.get(0)?, &[1, 2, 1], 2)?;
+        assert_eq!(masked.to_vec1::<f32>()?[2], f32::NEG_INFINITY);
+        check_finite_logits(&masked)?;
+
+        for invalid in [f32::NAN, f32::INFINITY] {
+            let logits = Tensor::new(&[[0.5f32, 1., 2.], [1., invalid, 0.]], &device)?;
+            let err = check_finite_logits(&logits).unwrap_err();
+            assert!(err.to_string().contains("batch index 1"), "{err}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+//! Layout helpers for the paged KV cache.
+//!
+//! The key cache has shape `[num_blocks, num_kv_heads, head_size / x, block_size, x]`,
+//! where `x` packs 16 bytes worth of elements together for vectorized loads,
+//! and the value cache has shape `[num_blocks, num_kv_heads, head_size, block_size]`.
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use candle_core::{bail, DType, Error, Result, Tensor};
+
+use crate::slot_mapping::{block_positions, BlockPosition};
+
+/// Number of bytes packed together in the last dimension of the key cache.
+pub const KEY_CACHE_PACKING_BYTES: usize = 16;
+
+/// Storage format of the KV cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KvCacheDtype {
+    /// Store keys and values in the model's compute dtype
+    #[default]
+    Auto,
+    /// fp8 with 5 exponent and 2 mantissa bits
+    Fp8E5M2,
+    /// fp8 with 4 exponent and 3 mantissa bits
+    Fp8E4M3,
+}
+
+impl KvCacheDtype {
+    /// The string identifying this format, as accepted by [`FromStr`].
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Fp8E5M2 => "fp8_e5m2",
+            Self::Fp8E4M3 => "fp8_e4m3",
+        }
+    }
+
+    /// Returns true if keys and values are quantized to fp8.
+    pub fn is_fp8(&self) -> bool {
+        !matches!(self, Self::Auto)
+    }
+}
+
+impl FromStr for KvCacheDtype {
+    type Err = Error;
+
+    /// Parses `"auto"`, `"fp8_e5m2"` or `"fp8_e4m3"`, as well as `"fp8"` as an
+    /// alias of `"fp8_e4m3"`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "fp8_e5m2" => Ok(Self::Fp8E5M2),
+            "fp8" | "fp8_e4m3" => Ok(Self::Fp8E4M3),
+            _ => {
+                bail!("unsupported kv cache dtype {s:?}, expected one of auto, fp8_e5m2, fp8_e4m3")
+            }
+        }
+    }
+}
+
+impl fmt::Display for KvCacheDtype {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Returns the packing factor `x` of a key cache, so that callers don't need
+/// to know how head dimensions are packed.
+///
+/// Errors if `x` doesn't pack [`KEY_CACHE_PACKING_BYTES`] of the cache dtype,
+/// or if the derived head size differs from the query's `head_size`.
+pub fn key_cache_packing(key_cache: &Tensor, head_size: usize) -> Result<usize> {
+    let (_num_blocks, _num_kv_heads, packed_head_size, _block_size, x) = key_cache.dims5()?;
+    let expected_x = KEY_CACHE_PACKING_BYTES / key_cache.dtype().size_in_bytes();
+    if x != expected_x {
+        bail!(
+            "key cache packs {x} elements per head chunk, expected {expected_x} for {:?}",
+            key_cache.dtype()
+        )
+    }
+    let cache_head_size = packed_head_size * x;
+    if cache_head_size != head_size {
+        bail!("key cache head size {cache_head_size} does not match query head size {head_size}")
+    }
+    Ok(x)
+}
+
+/// Dimensions of a paged KV cache, as derived from its key and value tensors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KvCacheDims {
+    /// Number of physical blocks
+    pub num_blocks: usize,
+    /// Number of key/value heads
+    pub num_kv_heads: usize,
+    /// Size of each attention head
+    pub head_size: usize,
+    /// Number of tokens stored per block
+    pub block_size: usize,
+    /// Number of head elements packed together in the key cache
+    pub x: usize,
+}
+
+/// Derives the [`KvCacheDims`] of a key and value cache pair, checking that
+/// both caches share a dtype and describe the same blocks, heads and head
+/// size.
+pub fn describe_kv_cache(key_cache: &Tensor, value_cache: &Tensor) -> Result<KvCacheDims> {
+    let (num_blocks, num_kv_heads, packed_head_size, block_size, x) = key_cache.dims5()?;
+    let head_size = packed_head_size * x;
+    key_cache_packing(key_cache, head_size)?;
+    if value_cache.dtype() != key_cache.dtype() {
+        bail!(
+            "value cache dtype {:?} does not match key cache dtype {:?}",
+            value_cache.dtype(),
+            key_cache.dtype()
+        )
+    }
+    if value_cache.dims() != [num_blocks, num_kv_heads, head_size, block_size] {
+        bail!(
+            "value cache shape {:?} is inconsistent with key cache shape {:?}",
+            value_cache.shape(),
+            key_cache.shape()
+        )
+    }
+    Ok(KvCacheDims {
+        num_blocks,
+        num_kv_heads,
+        head_size,
+        block_size,
+        x,
+    })
+}
+
+/// Zeroes the physical blocks `block_ids` in place in every cache of
+/// `kv_caches`, so that stale keys and values can't leak into a sequence
+/// that reuses those blocks.
+///
+/// Each cache must be contiguous with the block index as its first
+/// dimension, which holds for both key and value caches. Contiguity and all
+/// block ids are validated against every cache before anything is written.
+/// Runs of contiguous block ids are zeroed with a single write per cache,
+/// from one zeros tensor shared by all caches of the same shape and dtype.
+pub fn zero_blocks(kv_caches: &[Tensor], block_ids: &[usize]) -> Result<()> {
+    for (i, kv_cache) in kv_caches.iter().enumerate() {
+        if !kv_cache.is_contiguous() {
+            bail!("cache {i} must be contiguous to be zeroed in place")
+        }
+        let num_blocks = kv_cache.dim(0)?;
+        if let Some(block_id) = block_ids.iter().find(|block_id| **block_id >= num_blocks) {
+            bail!("block {block_id} is out of range for a cache with {num_blocks} blocks")
+        }
+    }
+    let runs = block_runs(block_ids);
+    let Some(max_run_len) = runs.iter().map(|(_, len)| *len).max() else {
+        return Ok(());
+    };
+    let mut zeros: Vec<Tensor> = Vec::new();
+    for kv_cache in kv_caches {
+        let mut run_shape = kv_cache.dims().to_vec();
+        run_shape[0] = max_run_len;
+        let shared = zeros.iter().find(|zeros| {
+            zeros.dims() == run_shape
+                && zeros.dtype() == kv_cache.dtype()
+                && zeros.device().same_device(kv_cache.device())
+        });
+        let run_zeros = match shared {
+            Some(run_zeros) => run_zeros.clone(),
+            None => {
+                let run_zeros = Tensor::zeros(run_shape, kv_cache.dtype(), kv_cache.device())?;
+                zeros.push(run_zeros.clone());
+                run_zeros
+            }
+        };
+        for (start, len) in &runs {
+            kv_cache.slice_set(&run_zeros.narrow(0, 0, *len)?, 0, *start)?;
+        }
+    }
+    Ok(())
+}
+
+/// Groups `block_ids` into sorted runs of contiguous ids, as `(start, len)`
+/// pairs. Duplicate ids are merged.
+fn block_runs(block_ids: &[usize]) -> Vec<(usize, usize)> {
+    let mut block_ids = block_ids.to_vec();
+    block_ids.sort_unstable();
+    block_ids.dedup();
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for block_id in block_ids {
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == block_id => *len += 1,
+            _ => runs.push((block_id, 1)),
+        }
+    }
+    runs
+}
+
+/// CPU reference for the paged cache write.
+///
+/// Scatters `key` and `value`, both of shape `[num_tokens, num_kv_heads, head_size]`,
+/// into `key_cache` and `value_cache` in place, at the slots given by the
+/// `[num_tokens]` i64 `slot_mapping`. Tokens with a negative slot are skipped.
+/// All tensors must live on the CPU and the caches must be contiguous. The
+/// scatter runs in f32, which is exact for f16 and bf16 caches, so only f32,
+/// f16 and bf16 caches are accepted.
+///
+/// Only the blocks written to are copied out of and back into the caches, so
+/// a call costs `O(num_written_blocks * num_kv_heads * head_size * block_size)`
+/// regardless of the cache size.
+///
+/// This serves as the golden reference for the CUDA kernel and lets the slot
+/// mapping logic be validated without a GPU.
+pub fn reshape_and_cache_cpu(
+    key: &Tensor,
+    value: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    slot_mapping: &Tensor,
+) -> Result<()> {
+    for tensor in [key, value, key_cache, value_cache, slot_mapping] {
+        if !tensor.device().is_cpu() {
+            bail!("reshape_and_cache_cpu expects all tensors on the CPU")
+        }
+    }
+    let (num_tokens, num_kv_heads, head_size) = key.dims3()?;
+    if value.dims() != key.dims() {
+        bail!(
+            "key shape {:?} does not match value shape {:?}",
+            key.shape(),
+            value.shape()
+        )
+    }
+    let KvCacheDims {
+        num_blocks,
+        num_kv_heads: cache_num_kv_heads,
+        head_size: cache_head_size,
+        block_size,
+        x,
+    } = describe_kv_cache(key_cache, value_cache)?;
+    if (cache_num_kv_heads, cache_head_size) != (num_kv_heads, head_size) {
+        bail!(
+            "caches hold {cache_num_kv_heads} kv heads of size {cache_head_size}, but key has {num_kv_heads} heads of size {head_size}"
+        )
+    }
+    let dtype = key_cache.dtype();
+    if !matches!(dtype, DType::F32 | DType::F16 | DType::BF16) {
+        bail!("reshape_and_cache_cpu supports f32, f16 and bf16 caches, got {dtype:?}")
+    }
+    if key.dtype() != dtype || value.dtype() != dtype {
+        bail!(
+            "key ({:?}) and value ({:?}) must share the caches' dtype {dtype:?}",
+            key.dtype(),
+            value.dtype()
+        )
+    }
+    if slot_mapping.dtype() != DType::I64 {
+        bail!("slot_mapping must be i64, got {:?}", slot_mapping.dtype())
+    }
+    let slot_mapping = slot_mapping.to_vec1::<i64>()?;
+    if slot_mapping.len() != num_tokens {
+        bail!(
+            "slot_mapping has {} entries for {num_tokens} tokens",
+            slot_mapping.len()
+        )
+    }
+    let num_slots = num_blocks * block_size;
+    if let Some(slot) = slot_mapping.iter().find(|slot| **slot >= num_slots as i64) {
+        bail!("slot {slot} is out of range for a cache with {num_slots} slots")
+    }
+
+    let key = to_f32_vec(key)?;
+    let value = to_f32_vec(value)?;
+
+    // Group the tokens by the block they are written to, keeping their order
+    // so that the last token wins when several share a slot
+    let mut tokens_per_block = BTreeMap::<usize, Vec<(usize, usize)>>::new();
+    for (token, position) in block_positions(&slot_mapping, block_size)?
+        .into_iter()
+        .enumerate()
+    {
+        if let Some(BlockPosition { block, offset }) = position {
+            tokens_per_block
+                .entry(block)
+                .or_default()
+                .push((token, offset));
+        }
+    }
+
+    for (block, tokens) in tokens_per_block {
+        let key_block = key_cache.narrow(0, block, 1)?;
+        let value_block = value_cache.narrow(0, block, 1)?;
+        let mut key_block_data = to_f32_vec(&key_block)?;
+        let mut value_block_data = to_f32_vec(&value_block)?;
+        for (token, offset) in tokens {
+            for head in 0..num_kv_heads {
+                for i in 0..head_size {
+                    let src = (token * num_kv_heads + head) * head_size + i;
+                    let key_chunk = (head * (head_size / x) + i / x) * block_size + offset;
+                    let value_dst = (head * head_size + i) * block_size + offset;
+                    key_block_data[key_chunk * x + i % x] = key[src];
+                    value_block_data[value_dst] = value[src];
+                }
+            }
+        }
+        let device = key_cache.device();
+        let key_block = Tensor::from_vec(key_block_data, key_block.shape(), device)?;
+        let value_block = Tensor::from_vec(value_block_data, value_block.shape(), device)?;
+        key_cache.slice_set(&key_block.to_dtype(dtype)?, 0, block)?;
+        value_cache.slice_set(&value_block.to_dtype(dtype)?, 0, block)?;
+    }
+    Ok(())
+}
+
+fn to_f32_vec(tensor: &Tensor) -> Result<Vec<f32>> {
+    tensor.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{Device, IndexOp};
+
+    use super::*;
+
+    #[test]
+    fn test_kv_cache_dtype_from_str() -> Result<()> {
+        for dtype in [
+            KvCacheDtype::Auto,
+            KvCacheDtype::Fp8E5M2,
+            KvCacheDtype::Fp8E4M3,
+        ] {
+            assert_eq!(dtype.to_string().parse::<KvCacheDtype>()?, dtype);
+        }
+        assert_eq!("fp8".parse::<KvCacheDtype>()?, KvCacheDtype::Fp8E4M3);
+        for unknown in ["", "fp16", "FP8_E4M3", "fp8_e4m3 "] {
+            assert!(unknown.parse::<KvCacheDtype>().is_err(), "{unknown:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_cache_packing() -> Result<()> {
+        let device = Device::Cpu;
+        let (num_blocks, num_kv_heads, head_size, block_size) = (4, 2, 64, 16);
+
+        let f16_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size / 8, block_size, 8),
+            DType::F16,
+            &device,
+        )?;
+        assert_eq!(key_cache_packing(&f16_cache, head_size)?, 8);
+
+        let f32_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size / 4, block_size, 4),
+            DType::F32,
+            &device,
+        )?;
+        assert_eq!(key_cache_packing(&f32_cache, head_size)?, 4);
+
+        // f32 elements packed as if they were f16
+        let mispacked_cache = f16_cache.to_dtype(DType::F32)?;
+        assert!(key_cache_packing(&mispacked_cache, head_size).is_err());
+
+        assert!(key_cache_packing(&f16_cache, 128).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_kv_cache() -> Result<()> {
+        let device = Device::Cpu;
+        let (num_blocks, num_kv_heads, head_size, block_size) = (5, 4, 64, 16);
+        let key_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size / 8, block_size, 8),
+            DType::F16,
+            &device,
+        )?;
+        let value_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size, block_size),
+            DType::F16,
+            &device,
+        )?;
+        assert_eq!(
+            describe_kv_cache(&key_cache, &value_cache)?,
+            KvCacheDims {
+                num_blocks,
+                num_kv_heads,
+                head_size,
+                block_size,
+                x: 8,
+            }
+        );
+
+        let fewer_blocks = value_cache.narrow(0, 0, num_blocks - 1)?;
+        assert!(describe_kv_cache(&key_cache, &fewer_blocks).is_err());
+        let transposed = value_cache.transpose(2, 3)?;
+        assert!(describe_kv_cache(&key_cache, &transposed).is_err());
+        let f32_values = value_cache.to_dtype(DType::F32)?;
+        assert!(describe_kv_cache(&key_cache, &f32_values).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_zero_blocks() -> Result<()> {
+        let device = Device::Cpu;
+        let key_cache = Tensor::ones((6, 2, 4, 4, 4), DType::F32, &device)?;
+        let value_cache = Tensor::ones((6, 2, 16, 4), DType::F32, &device)?;
+        zero_blocks(&[key_cache.clone(), value_cache.clone()], &[4, 1, 2, 2])?;
+
+        for cache in [&key_cache, &value_cache] {
+            let block_sums = cache.flatten_from(1)?.sum(1)?.to_vec1::<f32>()?;
+            let block_size = cache.flatten_from(1)?.dim(1)? as f32;
+            assert_eq!(block_sums, [block_size, 0., 0., block_size, 0., block_size]);
+        }
+        assert_eq!(block_runs(&[4, 1, 2, 2]), [(1, 2), (4, 1)]);
+        assert!(zero_blocks(&[key_cache], &[6]).is_err());
+
+        // A non-contiguous cache is rejected before the first cache is written
+        let untouched = Tensor::ones((6, 2, 16, 4), DType::F32, &device)?;
+        let transposed = Tensor::ones((6, 2, 4, 16), DType::F32, &device)?.transpose(2, 3)?;
+        assert!(zero_blocks(&[untouched.clone(), transposed], &[0]).is_err());
+        assert_eq!(untouched.sum_all()?.to_scalar::<f32>()?, 6. * 2. * 16. * 4.);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reshape_and_cache_cpu() -> Result<()> {
+        let device = Device::Cpu;
+        let (num_blocks, num_kv_heads, head_size, block_size) = (3, 2, 8, 4);
+        let x = 4;
+        let key_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size / x, block_size, x),
+            DType::F32,
+            &device,
+        )?;
+        let value_cache = Tensor::zeros(
+            (num_blocks, num_kv_heads, head_size, block_size),
+            DType::F32,
+            &device,
+        )?;
+        let num_elements = num_kv_heads * head_size;
+        let key = Tensor::arange(0f32, 2. * num_elements as f32, &device)?.reshape((
+            2,
+            num_kv_heads,
+            head_size,
+        ))?;
+        let value = (&key + 100.)?;
+        // slot 6 is block 1, offset 2; the second token is padding
+        let slot_mapping = Tensor::new(&[6i64, -1], &device)?;
+        reshape_and_cache_cpu(&key, &value, &key_cache, &value_cache, &slot_mapping)?;
+
+        let written_key = key_cache
+            .i((1, .., .., 2, ..))?
+            .reshape((num_kv_heads, head_size))?;
+        let written_value = value_cache.i((1, .., .., 2))?;
+        assert_eq!(written_key.to_vec2::<f32>()?, key.i(0)?.to_vec2::<f32>()?);
+        assert_eq!(
+            written_value.to_vec2::<f32>()?,
+            value.i(0)?.to_vec2::<f32>()?
+        );
+        // nothing else was written
+        let total = |t: &Tensor| t.sum_all()?.to_scalar::<f32>();
+        assert_eq!(total(&key_cache)?, total(&key.i(0)?)?);
+        assert_eq!(total(&value_cache)?, total(&value.i(0)?)?);
+
+        let f64_key_cache = key_cache.to_dtype(DType::F64)?;
+        let f64_value_cache = value_cache.to_dtype(DType::F64)?;
+        assert!(reshape_and_cache_cpu(
+            &key.to_dtype(DType::F64)?,
+            &value.to_dtype(DType::F64)?,
+            &f64_key_cache,
+            &f64_value_cache,
+            &slot_mapping,
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reshape_and_cache_cpu_single_kv_head() -> Result<()> {
+        let device = Device::Cpu;
+        let (num_blocks, head_size, block_size, x) = (4, 16, 4, 8);
+        let key_cache = Tensor::zeros(
+            (num_blocks, 1, head_size / x, block_size, x),
+            DType::F16,
+            &device,
+        )?;
+        let value_cache =
+            Tensor::zeros((num_blocks, 1, head_size, block_size), DType::F16, &device)?;
+        let num_tokens = 3;
+        let key = Tensor::arange(1f32, (num_tokens * head_size + 1) as f32, &device)?
+            .reshape((num_tokens, 1, head_size))?
+            .to_dtype(DType::F16)?;
+        let value = key.neg()?;
+        // The last slot of block 0, the first of block 1 and one in block 3
+        let slots = [3i64, 4, 14];
+        let slot_mapping = Tensor::new(&slots, &device)?;
+        reshape_and_cache_cpu(&key, &value, &key_cache, &value_cache, &slot_mapping)?;
+
+        let f32_vec = |t: Tensor| t.to_dtype(DType::F32)?.to_vec1::<f32>();
+        let mut num_written = 0.;
+        for (token, slot) in slots.iter().enumerate() {
+            let (block, offset) = (*slot as usize / block_size, *slot as usize % block_size);
+            let written_key = key_cache.i((block, 0, .., offset, ..))?.flatten_all()?;
+            let written_value = value_cache.i((block, 0, .., offset))?;
+            assert_eq!(f32_vec(written_key)?, f32_vec(key.i((token, 0))?)?);
+            assert_eq!(f32_vec(written_value)?, f32_vec(value.i((token, 0))?)?);
+            num_written += head_size as f32;
+        }
+        let num_nonzero = |t: &Tensor| {
+            t.ne(0f64)?
+                .to_dtype(DType::F32)?
+                .sum_all()?
+                .to_scalar::<f32>()
+        };
+        assert_eq!(num_nonzero(&key_cache)?, num_written);
+        assert_eq!(num_nonzero(&value_cache)?, num_written);
+        // Block 2 was never written and is still zero
+        assert_eq!(num_nonzero(&key_cache.i(2)?)?, 0.);
+        Ok(())
+    }
+}
@@ -0,0 +1,8 @@
+pub mod config;
+pub mod device;
+pub mod generation;
+pub mod kv_cache;
+pub mod lm_head;
+pub mod metrics;
+pub mod rotary;
+pub mod slot_mapping;
@@ -0,0 +1,153 @@
+//! Language modeling head computed over chunks of the vocabulary.
+//!
+//! For large vocabularies the `[num_tokens, vocab_size]` logits of a single
+//! matmul are a significant memory spike. When only the best tokens are
+//! needed, these functions reduce each `[num_tokens, chunk_size]` chunk of
+//! logits as soon as it is computed, so the full logits never exist.
+
+use candle_core::{bail, DType, Result, Tensor, D};
+
+/// Computes the most likely token for each of the `[num_tokens, hidden_size]`
+/// `hidden_states`, without ever materializing the full logits.
+///
+/// The running maximum over the vocabulary chunks is kept on device, and
+/// ties resolve to the lowest token id, as with a single-shot argmax.
+pub fn chunked_argmax(
+    hidden_states: &Tensor,
+    weight: &Tensor,
+    chunk_size: usize,
+) -> Result<Vec<u32>> {
+    let (num_tokens, vocab_size) = check_shapes(hidden_states, weight, chunk_size)?;
+    let device = hidden_states.device();
+    let mut best_logits = Tensor::full(f32::NEG_INFINITY, num_tokens, device)?;
+    let mut best_tokens = Tensor::zeros(num_tokens, DType::U32, device)?;
+    for start in (0..vocab_size).step_by(chunk_size) {
+        let len = chunk_size.min(vocab_size - start);
+        let chunk = hidden_states
+            .matmul(&weight.narrow(0, start, len)?.t()?)?
+            .to_dtype(DType::F32)?;
+        let chunk_logits = chunk.max(D::Minus1)?;
+        let chunk_tokens = (chunk.argmax(D::Minus1)? + start as f64)?;
+        let improved = chunk_logits.gt(&best_logits)?;
+        best_logits = improved.where_cond(&chunk_logits, &best_logits)?;
+        best_tokens = improved.where_cond(&chunk_tokens, &best_tokens)?;
+    }
+    best_tokens.to_vec1::<u32>()
+}
+
+/// Computes the `k` most likely tokens for each of the `[num_tokens, hidden_size]`
+/// `hidden_states`, as `(token, logit)` pairs in decreasing order of logit,
+/// without ever materializing the full logits.
+///
+/// The running top `k` candidates are kept on device and merged with each
+/// vocabulary chunk, so at most `[num_tokens, k + chunk_size]` logits are
+/// alive at once. The merge picks the `k` best candidates by repeated argmax
+/// rather than sorting, so it is meant for small `k` and works for any
+/// `chunk_size`, unlike the sort kernel whose shared memory grows with the
+/// number of columns. The order of tied logits is unspecified.
+pub fn chunked_topk(
+    hidden_states: &Tensor,
+    weight: &Tensor,
+    chunk_size: usize,
+    k: usize,
+) -> Result<Vec<Vec<(u32, f32)>>> {
+    let (num_tokens, vocab_size) = check_shapes(hidden_states, weight, chunk_size)?;
+    if k == 0 || k > vocab_size {
+        bail!("k must be between 1 and the vocabulary size {vocab_size}, got {k}")
+    }
+    let device = hidden_states.device();
+    let masked = Tensor::full(f32::NEG_INFINITY, (num_tokens, 1), device)?;
+    let mut best_logits = Tensor::zeros((num_tokens, 0), DType::F32, device)?;
+    let mut best_tokens = Tensor::zeros((num_tokens, 0), DType::U32, device)?;
+    for start in (0..vocab_size).step_by(chunk_size) {
+        let len = chunk_size.min(vocab_size - start);
+        let chunk = hidden_states
+            .matmul(&weight.narrow(0, start, len)?.t()?)?
+            .to_dtype(DType::F32)?;
+        let chunk_tokens = Tensor::arange(start as u32, (start + len) as u32, device)?
+            .unsqueeze(0)?
+            .broadcast_as((num_tokens, len))?
+            .contiguous()?;
+        let logits = Tensor::cat(&[&best_logits, &chunk], 1)?;
+        let tokens = Tensor::cat(&[&best_tokens, &chunk_tokens], 1)?;
+        let mut kept_logits = vec![];
+        let mut kept_tokens = vec![];
+        for _ in 0..k.min(logits.dim(1)?) {
+            let best = logits.argmax_keepdim(D::Minus1)?;
+            kept_logits.push(logits.gather(&best, D::Minus1)?);
+            kept_tokens.push(tokens.gather(&best, D::Minus1)?);
+            logits.scatter_set(&best, &masked, D::Minus1)?;
+        }
+        best_logits = Tensor::cat(&kept_logits, 1)?;
+        best_tokens = Tensor::cat(&kept_tokens, 1)?;
+    }
+    let best_logits = best_logits.to_vec2::<f32>()?;
+    let best_tokens = best_tokens.to_vec2::<u32>()?;
+    Ok(best_tokens
+        .into_iter()
+        .zip(best_logits)
+        .map(|(tokens, logits)| tokens.into_iter().zip(logits).collect())
+        .collect())
+}
+
+fn check_shapes(
+    hidden_states: &Tensor,
+    weight: &Tensor,
+    chunk_size: usize,
+) -> Result<(usize, usize)> {
+    if chunk_size == 0 {
+        bail!("chunk_size must be greater than 0")
+    }
+    let (num_tokens, hidden_size) = hidden_states.dims2()?;
+    let (vocab_size, weight_hidden_size) = weight.dims2()?;
+    if vocab_size == 0 {
+        bail!("lm head weights have an empty vocabulary")
+    }
+    if hidden_size != weight_hidden_size {
+        bail!("hidden states of size {hidden_size} don't match lm head weights of size {weight_hidden_size}")
+    }
+    Ok((num_tokens, vocab_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::Device;
+
+    use super::*;
+
+    #[test]
+    fn test_chunked_matches_single_shot() -> Result<()> {
+        let device = Device::Cpu;
+        let (num_tokens, hidden_size, vocab_size, k) = (3, 16, 37, 5);
+        let hidden_states = Tensor::arange(0f32, (num_tokens * hidden_size) as f32, &device)?
+            .affine(0.7, 0.)?
+            .sin()?
+            .reshape((num_tokens, hidden_size))?;
+        let weight = Tensor::arange(0f32, (vocab_size * hidden_size) as f32, &device)?
+            .affine(0.13, 0.)?
+            .cos()?
+            .reshape((vocab_size, hidden_size))?;
+
+        let logits = hidden_states.matmul(&weight.t()?)?;
+        let argmax = logits.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let (sorted_logits, sorted_tokens) = logits.sort_last_dim(false)?;
+        let topk = sorted_tokens
+            .narrow(1, 0, k)?
+            .to_vec2::<u32>()?
+            .into_iter()
+            .zip(sorted_logits.narrow(1, 0, k)?.to_vec2::<f32>()?)
+            .map(|(tokens, logits)| tokens.into_iter().zip(logits).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        // Chunk sizes that divide the vocabulary, don't, and exceed it
+        for chunk_size in [1, 4, 10, 37, 64] {
+            assert_eq!(chunked_argmax(&hidden_states, &weight, chunk_size)?, argmax);
+            assert_eq!(chunked_topk(&hidden_states, &weight, chunk_size, k)?, topk);
+        }
+        assert!(chunked_topk(&hidden_states, &weight, 4, 0).is_err());
+        let empty_weight = weight.narrow(0, 0, 0)?;
+        assert!(chunked_argmax(&hidden_states, &empty_weight, 4).is_err());
+        assert!(chunked_topk(&hidden_states, &weight, 4, vocab_size + 1).is_err());
+        Ok(())
+    }
+}
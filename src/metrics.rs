@@ -0,0 +1,108 @@
+//! Counters describing the work done by forward passes, for a serving layer
+//! to scrape and export.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Forward pass metrics, updated with relaxed atomics so that recording is
+/// cheap and allocation-free on the hot path.
+#[derive(Debug, Default)]
+pub struct ForwardMetrics {
+    prefill_tokens: AtomicU64,
+    decode_tokens: AtomicU64,
+    forward_passes: AtomicU64,
+    total_forward_latency_micros: AtomicU64,
+    last_forward_latency_micros: AtomicU64,
+    kv_blocks_in_use: AtomicU64,
+}
+
+/// Point in time copy of [`ForwardMetrics`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Total number of prompt tokens processed
+    pub prefill_tokens: u64,
+    /// Total number of tokens decoded
+    pub decode_tokens: u64,
+    /// Number of forward passes recorded
+    pub forward_passes: u64,
+    /// Sum of the latencies of all forward passes, in microseconds
+    pub total_forward_latency_micros: u64,
+    /// Latency of the most recent forward pass, in microseconds
+    pub last_forward_latency_micros: u64,
+    /// Number of KV cache blocks currently allocated
+    pub kv_blocks_in_use: u64,
+}
+
+impl ForwardMetrics {
+    /// Creates a new set of metrics, with all counters at 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a forward pass over `num_prefill_tokens` prompt tokens and
+    /// `num_decode_tokens` decoded tokens, which took `latency`.
+    pub fn record_forward(
+        &self,
+        num_prefill_tokens: u64,
+        num_decode_tokens: u64,
+        latency: Duration,
+    ) {
+        let latency_micros = latency.as_micros().try_into().unwrap_or(u64::MAX);
+        self.prefill_tokens
+            .fetch_add(num_prefill_tokens, Ordering::Relaxed);
+        self.decode_tokens
+            .fetch_add(num_decode_tokens, Ordering::Relaxed);
+        self.forward_passes.fetch_add(1, Ordering::Relaxed);
+        self.total_forward_latency_micros
+            .fetch_add(latency_micros, Ordering::Relaxed);
+        self.last_forward_latency_micros
+            .store(latency_micros, Ordering::Relaxed);
+    }
+
+    /// Sets the number of KV cache blocks currently in use.
+    pub fn set_kv_blocks_in_use(&self, num_blocks: u64) {
+        self.kv_blocks_in_use.store(num_blocks, Ordering::Relaxed);
+    }
+
+    /// Returns the current value of every counter.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            prefill_tokens: self.prefill_tokens.load(Ordering::Relaxed),
+            decode_tokens: self.decode_tokens.load(Ordering::Relaxed),
+            forward_passes: self.forward_passes.load(Ordering::Relaxed),
+            total_forward_latency_micros: self.total_forward_latency_micros.load(Ordering::Relaxed),
+            last_forward_latency_micros: self.last_forward_latency_micros.load(Ordering::Relaxed),
+            kv_blocks_in_use: self.kv_blocks_in_use.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_steps_are_counted() {
+        let metrics = ForwardMetrics::new();
+        let (prompt_len, num_decode_steps) = (12, 5);
+        metrics.record_forward(prompt_len, 0, Duration::from_micros(900));
+        for step in 0..num_decode_steps {
+            metrics.record_forward(0, 1, Duration::from_micros(100 + step));
+        }
+        metrics.set_kv_blocks_in_use(2);
+
+        assert_eq!(
+            metrics.snapshot(),
+            MetricsSnapshot {
+                prefill_tokens: prompt_len,
+                decode_tokens: num_decode_steps,
+                forward_passes: num_decode_steps + 1,
+                total_forward_latency_micros: 900 + 100 + 101 + 102 + 103 + 104,
+                last_forward_latency_micros: 104,
+                kv_blocks_in_use: 2,
+            }
+        );
+    }
+}
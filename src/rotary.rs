@@ -0,0 +1,604 @@
+//! Rotary position embeddings shared by all models.
+//!
+//! Supports both the NeoX layout, which rotates the two halves of each head
+//! against each other, and the GPT-J layout, which rotates interleaved pairs
+//! of dimensions. Only the first `rotary_dim` dimensions of each head are
+//! rotated, the rest pass through unchanged.
+
+use std::{f32::consts::PI, ops::Range};
+
+use candle_core::{bail, DType, Device, Result, Tensor, D};
+use candle_nn::rotary_emb::{rope, rope_i};
+use candle_transformers::models::llama::{Config, Llama3RopeType};
+
+use crate::slot_mapping::checked_u32;
+
+/// How the rotated dimensions of a head are paired.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RotaryLayout {
+    /// Dimension `i` is paired with `i + rotary_dim / 2` (Llama, Mistral, NeoX)
+    #[default]
+    NeoX,
+    /// Dimension `2i` is paired with `2i + 1` (GPT-J)
+    GptJ,
+}
+
+/// Scaling of the rotary frequencies, used to extend the context length a
+/// model was trained on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RopeScaling {
+    /// Position interpolation, dividing every position by `factor`
+    Linear { factor: f32 },
+    /// NTK-aware scaling, which raises the base for a context `factor` times
+    /// longer than `original_max_position_embeddings`. As in vLLM, the base is
+    /// fixed for that extended context rather than recomputed from the length
+    /// of each batch, so a token's rotation never depends on the other
+    /// sequences of its batch or on earlier requests.
+    DynamicNtk {
+        factor: f32,
+        original_max_position_embeddings: usize,
+    },
+    /// Llama 3 scaling, which only slows down the low frequencies
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_position_embeddings: usize,
+    },
+}
+
+/// Configuration of a [`RotaryEmbedding`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RotaryConfig {
+    /// Size of each attention head
+    pub head_dim: usize,
+    /// Number of leading dimensions of each head that are rotated
+    pub rotary_dim: usize,
+    /// Base of the rotary frequencies (`rope_theta`)
+    pub base: f32,
+    /// Number of positions the cos/sin tables are precomputed for
+    pub max_position_embeddings: usize,
+    /// Pairing of the rotated dimensions
+    pub layout: RotaryLayout,
+    /// Optional frequency scaling
+    pub scaling: Option<RopeScaling>,
+}
+
+impl RotaryConfig {
+    /// Builds the rotary configuration of a Llama model, including its
+    /// Llama 3 rope scaling if any.
+    pub fn from_llama(config: &Config) -> Self {
+        let head_dim = config.hidden_size / config.num_attention_heads;
+        let scaling = config
+            .rope_scaling
+            .as_ref()
+            .filter(|rope_scaling| matches!(rope_scaling.rope_type, Llama3RopeType::Llama3))
+            .map(|rope_scaling| RopeScaling::Llama3 {
+                factor: rope_scaling.factor,
+                low_freq_factor: rope_scaling.low_freq_factor,
+                high_freq_factor: rope_scaling.high_freq_factor,
+                original_max_position_embeddings: rope_scaling.original_max_position_embeddings,
+            });
+        Self {
+            head_dim,
+            rotary_dim: head_dim,
+            base: config.rope_theta,
+            max_position_embeddings: config.max_position_embeddings,
+            layout: RotaryLayout::NeoX,
+            scaling,
+        }
+    }
+}
+
+/// Cos/sin tables, of shape `[num_positions, rotary_dim / 2]`.
+#[derive(Clone, Debug)]
+struct CosSin {
+    cos: Tensor,
+    sin: Tensor,
+}
+
+/// Rotary position embedding with precomputed cos/sin tables.
+#[derive(Debug)]
+pub struct RotaryEmbedding {
+    config: RotaryConfig,
+    /// Scaled rotary frequencies, in f32
+    inv_freq: Tensor,
+    cos_sin: CosSin,
+    dtype: DType,
+}
+
+impl RotaryEmbedding {
+    /// Precomputes the cos/sin tables for `config.max_position_embeddings`
+    /// positions, in `dtype` on `device`.
+    pub fn new(config: &RotaryConfig, dtype: DType, device: &Device) -> Result<Self> {
+        if config.rotary_dim == 0 || config.rotary_dim % 2 == 1 {
+            bail!(
+                "rotary_dim must be even and positive, got {}",
+                config.rotary_dim
+            )
+        }
+        if config.rotary_dim > config.head_dim {
+            bail!(
+                "rotary_dim {} is larger than head_dim {}",
+                config.rotary_dim,
+                config.head_dim
+            )
+        }
+        let inv_freq = Tensor::new(scaled_inv_freq(config), device)?;
+        let cos_sin = cos_sin(&inv_freq, 0..config.max_position_embeddings, dtype)?;
+        Ok(Self {
+            config: config.clone(),
+            inv_freq,
+            cos_sin,
+            dtype,
+        })
+    }
+
+    /// The configuration this embedding was built from.
+    pub fn config(&self) -> &RotaryConfig {
+        &self.config
+    }
+
+    /// Checks that a batch whose highest position is `max_position` fits in
+    /// the cos/sin tables.
+    ///
+    /// [`Self::apply`] doesn't read its positions back from the device, so the
+    /// caller is expected to run this once per forward pass, with the highest
+    /// position it already knows from the batch's metadata.
+    pub fn check_max_position(&self, max_position: usize) -> Result<()> {
+        let num_table_positions = self.config.max_position_embeddings;
+        if max_position >= num_table_positions {
+            bail!(
+                "position {max_position} is out of range for rotary tables of {num_table_positions} positions"
+            )
+        }
+        Ok(())
+    }
+
+    /// Rotates `x`, of shape `[batch_size, num_heads, num_tokens, head_dim]`,
+    /// according to the `[num_tokens]` u32 or i64 `positions` of its tokens.
+    ///
+    /// Positions must be non-negative and below `max_position_embeddings`,
+    /// which [`Self::check_max_position`] validates without a device sync.
+    pub fn apply(&self, x: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        let (_batch_size, _num_heads, num_tokens, head_dim) = x.dims4()?;
+        self.check_head_dim(head_dim)?;
+        let Some(CosSin { cos, sin }) = self.gather_cos_sin(positions, num_tokens)? else {
+            return Ok(x.clone());
+        };
+        self.rotate(x, &cos, &sin)
+    }
+
+    /// Rotates `x`, of shape `[num_tokens, num_heads, head_dim]`, according to
+    /// the `[num_tokens]` u32 or i64 `positions` of its tokens.
+    ///
+    /// This is the layout the projections produce and the KV cache is written
+    /// in, so no transposes are needed around the rotation. Positions follow
+    /// the same rules as in [`Self::apply`].
+    pub fn apply_thd(&self, x: &Tensor, positions: &Tensor) -> Result<Tensor> {
+        let (num_tokens, _num_heads, head_dim) = x.dims3()?;
+        self.check_head_dim(head_dim)?;
+        let Some(CosSin { cos, sin }) = self.gather_cos_sin(positions, num_tokens)? else {
+            return Ok(x.clone());
+        };
+        // Each token is rotated as a batch entry holding a single position
+        let rotated = self.rotate(&x.unsqueeze(2)?, &cos.unsqueeze(1)?, &sin.unsqueeze(1)?)?;
+        rotated.squeeze(2)
+    }
+
+    /// Rotates `x`, of shape `[batch_size, num_heads, num_tokens, head_dim]`,
+    /// whose tokens sit at the consecutive positions starting at
+    /// `start_position`, as in the decode steps of a single sequence.
+    ///
+    /// The cos/sin rows are computed from the rotary frequencies instead of
+    /// being gathered from the precomputed tables. This saves the gather for
+    /// the one token of a decode step, and positions are not limited to
+    /// `max_position_embeddings`.
+    pub fn apply_at(&self, x: &Tensor, start_position: usize) -> Result<Tensor> {
+        let (_batch_size, _num_heads, num_tokens, head_dim) = x.dims4()?;
+        self.check_head_dim(head_dim)?;
+        if num_tokens == 0 {
+            return Ok(x.clone());
+        }
+        let end_position = start_position + num_tokens;
+        checked_u32(end_position, "end position")?;
+        let CosSin { cos, sin } =
+            cos_sin(&self.inv_freq, start_position..end_position, self.dtype)?;
+        self.rotate(x, &cos, &sin)
+    }
+
+    fn check_head_dim(&self, head_dim: usize) -> Result<()> {
+        if head_dim != self.config.head_dim {
+            bail!(
+                "expected heads of size {}, got {head_dim}",
+                self.config.head_dim
+            )
+        }
+        Ok(())
+    }
+
+    /// Gathers the `[num_tokens, rotary_dim / 2]` cos/sin rows of `positions`,
+    /// or returns `None` when there are no tokens to rotate.
+    fn gather_cos_sin(&self, positions: &Tensor, num_tokens: usize) -> Result<Option<CosSin>> {
+        if !matches!(positions.dtype(), DType::U32 | DType::I64) {
+            bail!("positions must be u32 or i64, got {:?}", positions.dtype())
+        }
+        let positions = positions.flatten_all()?;
+        if positions.dim(0)? != num_tokens {
+            bail!(
+                "got {} positions for {num_tokens} tokens",
+                positions.dim(0)?
+            )
+        }
+        if num_tokens == 0 {
+            return Ok(None);
+        }
+        Ok(Some(CosSin {
+            cos: self.cos_sin.cos.index_select(&positions, 0)?,
+            sin: self.cos_sin.sin.index_select(&positions, 0)?,
+        }))
+    }
+
+    /// Rotates the first `rotary_dim` dimensions of the 4D `x` with the
+    /// layout's kernel, passing the others through.
+    fn rotate(&self, x: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let rotate = |x: &Tensor| match self.config.layout {
+            RotaryLayout::NeoX => rope(x, cos, sin),
+            RotaryLayout::GptJ => rope_i(x, cos, sin),
+        };
+        let head_dim = self.config.head_dim;
+        let rotary_dim = self.config.rotary_dim;
+        if rotary_dim == head_dim {
+            return rotate(&x.contiguous()?);
+        }
+        let rotated = rotate(&x.narrow(D::Minus1, 0, rotary_dim)?.contiguous()?)?;
+        let passed = x.narrow(D::Minus1, rotary_dim, head_dim - rotary_dim)?;
+        Tensor::cat(&[&rotated, &passed], D::Minus1)
+    }
+}
+
+/// Unscaled rotary frequencies, `base^(-2i / rotary_dim)`.
+fn inv_freq(rotary_dim: usize, base: f32) -> Vec<f32> {
+    (0..rotary_dim)
+        .step_by(2)
+        .map(|i| 1. / base.powf(i as f32 / rotary_dim as f32))
+        .collect()
+}
+
+/// Rotary frequencies with the scaling of `config` applied.
+fn scaled_inv_freq(config: &RotaryConfig) -> Vec<f32> {
+    let base = match config.scaling {
+        Some(RopeScaling::DynamicNtk {
+            factor,
+            original_max_position_embeddings,
+        }) => {
+            let original_max_position_embeddings = original_max_position_embeddings as f32;
+            let max_len = original_max_position_embeddings * factor;
+            let rotary_dim = config.rotary_dim as f32;
+            config.base
+                * (factor * max_len / original_max_position_embeddings - (factor - 1.))
+                    .powf(rotary_dim / (rotary_dim - 2.))
+        }
+        _ => config.base,
+    };
+    let inv_freq = inv_freq(config.rotary_dim, base);
+    match config.scaling {
+        None | Some(RopeScaling::DynamicNtk { .. }) => inv_freq,
+        Some(RopeScaling::Linear { factor }) => {
+            inv_freq.into_iter().map(|freq| freq / factor).collect()
+        }
+        Some(RopeScaling::Llama3 {
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings,
+        }) => {
+            let original_max_position_embeddings = original_max_position_embeddings as f32;
+            let low_freq_wavelen = original_max_position_embeddings / low_freq_factor;
+            let high_freq_wavelen = original_max_position_embeddings / high_freq_factor;
+            inv_freq
+                .into_iter()
+                .map(|freq| {
+                    let wavelen = 2. * PI / freq;
+                    if wavelen < high_freq_wavelen {
+                        freq
+                    } else if wavelen > low_freq_wavelen {
+                        freq / factor
+                    } else {
+                        let smooth = (original_max_position_embeddings / wavelen - low_freq_factor)
+                            / (high_freq_factor - low_freq_factor);
+                        (1. - smooth) * freq / factor + smooth * freq
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+/// Computes the cos/sin rows of the `[rotary_dim / 2]` f32 `inv_freq` for
+/// positions `positions.start..positions.end`.
+fn cos_sin(inv_freq: &Tensor, positions: Range<usize>, dtype: DType) -> Result<CosSin> {
+    let freqs = Tensor::arange(
+        positions.start as u32,
+        positions.end as u32,
+        inv_freq.device(),
+    )?
+    .to_dtype(DType::F32)?
+    .unsqueeze(1)?
+    .broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+    Ok(CosSin {
+        cos: freqs.cos()?.to_dtype(dtype)?,
+        sin: freqs.sin()?.to_dtype(dtype)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotary_config(head_dim: usize, rotary_dim: usize, layout: RotaryLayout) -> RotaryConfig {
+        RotaryConfig {
+            head_dim,
+            rotary_dim,
+            base: 100.,
+            max_position_embeddings: 16,
+            layout,
+            scaling: None,
+        }
+    }
+
+    /// Rotates each pair of dimensions of `x` by `position * inv_freq[i]`,
+    /// one scalar at a time.
+    fn reference_rotation(
+        x: &[f32],
+        position: usize,
+        inv_freq: &[f32],
+        layout: RotaryLayout,
+    ) -> Vec<f32> {
+        let mut rotated = x.to_vec();
+        let half = inv_freq.len();
+        for (i, freq) in inv_freq.iter().enumerate() {
+            let (first, second) = match layout {
+                RotaryLayout::NeoX => (i, i + half),
+                RotaryLayout::GptJ => (2 * i, 2 * i + 1),
+            };
+            let (sin, cos) = (position as f32 * freq).sin_cos();
+            rotated[first] = x[first] * cos - x[second] * sin;
+            rotated[second] = x[second] * cos + x[first] * sin;
+        }
+        rotated
+    }
+
+    /// Checks that `RotaryEmbedding::apply` rotates every token as
+    /// [`reference_rotation`] does with the expected `inv_freq`.
+    fn check_rotation(config: &RotaryConfig, positions: &[u32], inv_freq: &[f32]) -> Result<()> {
+        let device = Device::Cpu;
+        let (num_heads, num_tokens, head_dim) = (2, positions.len(), config.head_dim);
+        let x = Tensor::arange(0f32, (num_heads * num_tokens * head_dim) as f32, &device)?
+            .affine(0.3, 0.)?
+            .sin()?
+            .reshape((1, num_heads, num_tokens, head_dim))?;
+        let rotary = RotaryEmbedding::new(config, DType::F32, &device)?;
+        let rotated = rotary
+            .apply(&x, &Tensor::new(positions, &device)?)?
+            .flatten_to(2)?
+            .to_vec2::<f32>()?;
+
+        let x = x.flatten_to(2)?.to_vec2::<f32>()?;
+        for (row, (x, rotated)) in x.iter().zip(rotated).enumerate() {
+            let position = positions[row % num_tokens] as usize;
+            let expected = reference_rotation(x, position, inv_freq, config.layout);
+            for (rotated, expected) in rotated.iter().zip(expected) {
+                assert!(
+                    (rotated - expected).abs() < 1e-5,
+                    "got {rotated}, expected {expected} at position {position}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_neox_rotation() -> Result<()> {
+        let config = rotary_config(4, 4, RotaryLayout::NeoX);
+        check_rotation(&config, &[0, 3, 15], &[1., 0.1])
+    }
+
+    #[test]
+    fn test_gptj_rotation() -> Result<()> {
+        let config = rotary_config(4, 4, RotaryLayout::GptJ);
+        check_rotation(&config, &[0, 3, 15], &[1., 0.1])
+    }
+
+    #[test]
+    fn test_partial_rotary_dim() -> Result<()> {
+        // The last 2 dimensions of each head pass through unchanged
+        let config = rotary_config(6, 4, RotaryLayout::NeoX);
+        check_rotation(&config, &[1, 2, 9], &[1., 0.1])?;
+        let config = rotary_config(6, 4, RotaryLayout::GptJ);
+        check_rotation(&config, &[1, 2, 9], &[1., 0.1])
+    }
+
+    #[test]
+    fn test_linear_scaling() -> Result<()> {
+        let mut config = rotary_config(4, 4, RotaryLayout::NeoX);
+        config.scaling = Some(RopeScaling::Linear { factor: 2. });
+        check_rotation(&config, &[0, 5, 15], &[0.5, 0.05])
+    }
+
+    #[test]
+    fn test_llama3_scaling() -> Result<()> {
+        let mut config = rotary_config(6, 6, RotaryLayout::NeoX);
+        let (factor, low_freq_factor, high_freq_factor, original_max_position_embeddings) =
+            (8., 1., 4., 64.);
+        config.scaling = Some(RopeScaling::Llama3 {
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings: 64,
+        });
+        // With base 100, the unscaled wavelengths are 6.3, 29.2 and 135.4.
+        // The first is below the high frequency wavelength 64 / 4 = 16 and is
+        // kept, the last is above the low frequency wavelength 64 / 1 and is
+        // divided by the factor, the middle one is interpolated.
+        let middle_freq = 100f32.powf(-1. / 3.);
+        let smooth = (original_max_position_embeddings * middle_freq / (2. * PI) - low_freq_factor)
+            / (high_freq_factor - low_freq_factor);
+        let inv_freq = [
+            1.,
+            (1. - smooth) * middle_freq / factor + smooth * middle_freq,
+            100f32.powf(-2. / 3.) / factor,
+        ];
+        check_rotation(&config, &[0, 7, 15], &inv_freq)
+    }
+
+    #[test]
+    fn test_dynamic_ntk_scaling() -> Result<()> {
+        let mut config = rotary_config(4, 4, RotaryLayout::NeoX);
+        config.scaling = Some(RopeScaling::DynamicNtk {
+            factor: 2.,
+            original_max_position_embeddings: 8,
+        });
+        // The base is fixed for the extended context of 2 * 8 positions, at
+        // 100 * (2 * 16 / 8 - 1)^(4 / 2) = 30^2, whatever the positions
+        check_rotation(&config, &[0, 7], &[1., 1. / 30.])?;
+        check_rotation(&config, &[0, 4, 15], &[1., 1. / 30.])
+    }
+
+    #[test]
+    fn test_dynamic_ntk_scaling_is_stateless() -> Result<()> {
+        let device = Device::Cpu;
+        let mut config = rotary_config(4, 4, RotaryLayout::NeoX);
+        config.scaling = Some(RopeScaling::DynamicNtk {
+            factor: 2.,
+            original_max_position_embeddings: 8,
+        });
+        let x = Tensor::arange(0f32, 16., &device)?
+            .sin()?
+            .reshape((1, 2, 2, 4))?;
+        let short_positions = Tensor::new(&[2u32, 9], &device)?;
+        let expected = RotaryEmbedding::new(&config, DType::F32, &device)?
+            .apply(&x, &short_positions)?
+            .squeeze(0)?
+            .to_vec3::<f32>()?;
+
+        // A long sequence rotated first doesn't change how a short one is
+        let rotary = RotaryEmbedding::new(&config, DType::F32, &device)?;
+        rotary.apply(&x, &Tensor::new(&[14u32, 15], &device)?)?;
+        let short = rotary
+            .apply(&x, &short_positions)?
+            .squeeze(0)?
+            .to_vec3::<f32>()?;
+        assert_eq!(short, expected);
+
+        // nor does a long sequence batched with it
+        let batched = rotary
+            .apply(&x, &Tensor::new(&[2u32, 15], &device)?)?
+            .narrow(2, 0, 1)?;
+        let alone = rotary.apply(&x.narrow(2, 0, 1)?, &Tensor::new(&[2u32], &device)?)?;
+        assert_eq!(
+            batched.squeeze(0)?.to_vec3::<f32>()?,
+            alone.squeeze(0)?.to_vec3::<f32>()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_position_bounds() -> Result<()> {
+        let device = Device::Cpu;
+        let config = rotary_config(4, 4, RotaryLayout::NeoX);
+        let rotary = RotaryEmbedding::new(&config, DType::F32, &device)?;
+
+        rotary.check_max_position(15)?;
+        let err = rotary.check_max_position(16).unwrap_err();
+        assert!(
+            err.to_string().contains("position 16 is out of range"),
+            "{err}"
+        );
+
+        let empty = Tensor::zeros((1, 2, 0, 4), DType::F32, &device)?;
+        let positions = Tensor::zeros(0, DType::U32, &device)?;
+        assert_eq!(rotary.apply(&empty, &positions)?.dims(), [1, 2, 0, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_thd_matches_transposed_apply() -> Result<()> {
+        let device = Device::Cpu;
+        let (num_tokens, num_heads) = (3, 2);
+        let positions = Tensor::new(&[4u32, 0, 9], &device)?;
+        for (head_dim, rotary_dim, layout) in [
+            (4, 4, RotaryLayout::NeoX),
+            (4, 4, RotaryLayout::GptJ),
+            (6, 4, RotaryLayout::NeoX),
+            (6, 4, RotaryLayout::GptJ),
+        ] {
+            let config = rotary_config(head_dim, rotary_dim, layout);
+            let rotary = RotaryEmbedding::new(&config, DType::F32, &device)?;
+            let x = Tensor::arange(0f32, (num_tokens * num_heads * head_dim) as f32, &device)?
+                .affine(0.3, 0.)?
+                .sin()?
+                .reshape((num_tokens, num_heads, head_dim))?;
+
+            let thd = rotary.apply_thd(&x, &positions)?;
+            let transposed = rotary
+                .apply(&x.transpose(0, 1)?.unsqueeze(0)?, &positions)?
+                .squeeze(0)?
+                .transpose(0, 1)?;
+            let max_diff = (thd - transposed)?.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(max_diff < 1e-6, "{layout:?} with rotary_dim {rotary_dim}");
+        }
+
+        let rotary = RotaryEmbedding::new(
+            &rotary_config(4, 4, RotaryLayout::NeoX),
+            DType::F32,
+            &device,
+        )?;
+        let x = Tensor::ones((num_tokens, num_heads, 4), DType::F32, &device)?;
+        assert!(rotary.apply_thd(&x.unsqueeze(0)?, &positions).is_err());
+        assert!(rotary.apply_thd(&x.narrow(2, 0, 2)?, &positions).is_err());
+        assert!(rotary.apply_thd(&x, &positions.narrow(0, 0, 2)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_at_matches_cached_tables() -> Result<()> {
+        let device = Device::Cpu;
+        let mut config = rotary_config(6, 4, RotaryLayout::GptJ);
+        config.scaling = Some(RopeScaling::Linear { factor: 2. });
+        for dtype in [DType::F32, DType::BF16] {
+            let rotary = RotaryEmbedding::new(&config, dtype, &device)?;
+            let x = Tensor::arange(0f32, 2. * 2. * 6., &device)?
+                .sin()?
+                .reshape((2, 2, 1, 6))?
+                .to_dtype(dtype)?;
+            // Single token decode steps, then a chunk of consecutive tokens
+            for position in [0u32, 1, 2, 15] {
+                let incremental = rotary.apply_at(&x, position as usize)?;
+                let cached = rotary.apply(&x, &Tensor::new(&[position], &device)?)?;
+                let diff = (incremental - cached)?.to_dtype(DType::F32)?.abs()?;
+                assert_eq!(
+                    diff.max_all()?.to_scalar::<f32>()?,
+                    0.,
+                    "position {position}"
+                );
+            }
+            let chunk = x.reshape((1, 2, 2, 6))?;
+            let incremental = rotary.apply_at(&chunk, 7)?;
+            let cached = rotary.apply(&chunk, &Tensor::new(&[7u32, 8], &device)?)?;
+            let diff = (incremental - cached)?.to_dtype(DType::F32)?.abs()?;
+            assert_eq!(diff.max_all()?.to_scalar::<f32>()?, 0.);
+        }
+
+        // Past the tables, rows are still computed from the frequencies
+        let rotary = RotaryEmbedding::new(&config, DType::F32, &device)?;
+        assert!(rotary.check_max_position(16).is_err());
+        let x = Tensor::ones((1, 1, 1, 6), DType::F32, &device)?;
+        let rotated = rotary.apply_at(&x, 20)?.flatten_all()?.to_vec1::<f32>()?;
+        let expected = reference_rotation(&[1.; 6], 20, &[0.5, 0.05], RotaryLayout::GptJ);
+        for (rotated, expected) in rotated.iter().zip(expected) {
+            assert!((rotated - expected).abs() < 1e-5);
+        }
+        Ok(())
+    }
+}
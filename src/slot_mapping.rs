@@ -0,0 +1,369 @@
+//! Helpers relating a slot mapping to the physical blocks of a paged KV cache.
+//!
+//! A slot is the flat index of a token's entry in the KV cache, i.e.
+//! `slot = block_index * block_size + block_offset`. Negative slots mark
+//! padding tokens, which the cache write skips.
+//!
+//! For a sequence of `sequence_length` tokens, token `i` is stored in the
+//! physical block `block_table[i / block_size]` at offset `i % block_size`.
+//! The block table therefore lists at least `sequence_length.div_ceil(block_size)`
+//! blocks, the last of which is partially filled when `sequence_length` is
+//! not a multiple of `block_size`. It may list more, when the allocator has
+//! already reserved the blocks the next tokens will be written to.
+//!
+//! Block tables of a batch are padded with [`BLOCK_TABLE_PADDING`] to the
+//! length of the longest one. As the padding is also a valid physical block,
+//! attention only tells padding apart through the sequence lengths, which must
+//! never extend into the padded region of their block table, i.e.
+//! `sequence_length <= block_table.len() * block_size`.
+
+use std::fmt::Display;
+
+use candle_core::{bail, Device, Result, Tensor};
+
+/// Position of a token in the paged KV cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlockPosition {
+    /// Physical block index
+    pub block: usize,
+    /// Offset of the token within its block
+    pub offset: usize,
+}
+
+/// Decomposes each slot of `slot_mapping` into the block and offset the
+/// cache write stores that token at, or `None` for padding tokens.
+///
+/// The serving layer can use this to update block tables after a write
+/// without redoing the slot arithmetic. Errors if a slot lands in a block
+/// whose index does not fit in the u32 block tables.
+pub fn block_positions(
+    slot_mapping: &[i64],
+    block_size: usize,
+) -> Result<Vec<Option<BlockPosition>>> {
+    if block_size == 0 {
+        bail!("block_size must be greater than 0")
+    }
+    let block_size = checked_i64(block_size, "block_size")?;
+    slot_mapping
+        .iter()
+        .map(|slot| {
+            if *slot < 0 {
+                return Ok(None);
+            }
+            Ok(Some(BlockPosition {
+                block: checked_u32(slot / block_size, "block index")? as usize,
+                offset: (slot % block_size) as usize,
+            }))
+        })
+        .collect()
+}
+
+/// Returns the distinct physical blocks written to by `slot_mapping`, in
+/// increasing order.
+///
+/// The block allocator can use the last entry to detect that a sequence has
+/// reached a new block and allocate the next one ahead of time.
+pub fn blocks_written(slot_mapping: &[i64], block_size: usize) -> Result<Vec<usize>> {
+    let mut blocks = block_positions(slot_mapping, block_size)?
+        .into_iter()
+        .flatten()
+        .map(|position| position.block)
+        .collect::<Vec<_>>();
+    blocks.sort_unstable();
+    blocks.dedup();
+    Ok(blocks)
+}
+
+/// Value padding the block tables of shorter sequences in a batch.
+pub const BLOCK_TABLE_PADDING: u32 = 0;
+
+/// Builds the `[num_sequences, max_num_blocks]` u32 block tables of a batch,
+/// padding shorter tables with [`BLOCK_TABLE_PADDING`].
+///
+/// Each block table must hold all the tokens of its sequence, i.e.
+/// `sequence_length <= block_table.len() * block_size`, so that no position
+/// within a sequence's length can reach the padded region, where a real
+/// block 0 and padding are indistinguishable. Tables may list pre-allocated
+/// blocks past the sequence's length.
+pub fn padded_block_tables(
+    block_tables: &[Vec<u32>],
+    sequence_lengths: &[usize],
+    block_size: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    if block_size == 0 {
+        bail!("block_size must be greater than 0")
+    }
+    if block_tables.len() != sequence_lengths.len() {
+        bail!(
+            "got {} block tables for {} sequences",
+            block_tables.len(),
+            sequence_lengths.len()
+        )
+    }
+    for (i, (block_table, sequence_length)) in block_tables.iter().zip(sequence_lengths).enumerate()
+    {
+        let num_blocks = sequence_length.div_ceil(block_size);
+        if block_table.len() < num_blocks {
+            bail!(
+                "sequence {i} of {sequence_length} tokens needs {num_blocks} blocks of size {block_size}, but its block table lists {}",
+                block_table.len()
+            )
+        }
+    }
+    let max_num_blocks = block_tables.iter().map(Vec::len).max().unwrap_or(0);
+    let padded = block_tables
+        .iter()
+        .flat_map(|block_table| {
+            block_table
+                .iter()
+                .copied()
+                .chain(std::iter::repeat(BLOCK_TABLE_PADDING))
+                .take(max_num_blocks)
+        })
+        .collect::<Vec<_>>();
+    Tensor::from_vec(padded, (block_tables.len(), max_num_blocks), device)
+}
+
+/// Checks that the slot mapping of a single sequence agrees with its block
+/// table and length, following the invariant documented in this module.
+///
+/// Once its negative padding slots are skipped, `slot_mapping` holds the
+/// slots of the last tokens of a sequence that is `sequence_length` tokens
+/// long after the write, in order.
+pub fn validate_sequence_slots(
+    slot_mapping: &[i64],
+    block_table: &[u32],
+    sequence_length: usize,
+    block_size: usize,
+) -> Result<()> {
+    if block_size == 0 {
+        bail!("block_size must be greater than 0")
+    }
+    let slot_mapping = slot_mapping
+        .iter()
+        .filter(|slot| **slot >= 0)
+        .collect::<Vec<_>>();
+    if slot_mapping.len() > sequence_length {
+        bail!(
+            "slot_mapping has {} non-padding entries for a sequence of {sequence_length} tokens",
+            slot_mapping.len()
+        )
+    }
+    check_block_table_covers(block_table, sequence_length, block_size)?;
+    let first_position = sequence_length - slot_mapping.len();
+    for (i, slot) in slot_mapping.iter().enumerate() {
+        let position = first_position + i;
+        let expected_slot = slot_for(
+            block_table[position / block_size],
+            block_size,
+            position % block_size,
+        )?;
+        if **slot != expected_slot {
+            bail!("token at position {position} maps to slot {slot}, expected {expected_slot}")
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the blocks of `block_table` hold all `sequence_length` tokens
+/// of its sequence, so that no position reaches the padding after it.
+fn check_block_table_covers(
+    block_table: &[u32],
+    sequence_length: usize,
+    block_size: usize,
+) -> Result<()> {
+    let num_blocks = sequence_length.div_ceil(block_size);
+    if block_table.len() < num_blocks {
+        bail!(
+            "a sequence of {sequence_length} tokens needs {num_blocks} blocks of size {block_size}, but its block table lists {}",
+            block_table.len()
+        )
+    }
+    Ok(())
+}
+
+/// Computes the slot of the token at `block_offset` in block `block_index`,
+/// erroring instead of wrapping if it overflows an `i64`.
+pub fn slot_for(block_index: u32, block_size: usize, block_offset: usize) -> Result<i64> {
+    if block_offset >= block_size {
+        bail!("block offset {block_offset} is out of range for blocks of size {block_size}")
+    }
+    let slot = (block_index as usize)
+        .checked_mul(block_size)
+        .and_then(|block_start| block_start.checked_add(block_offset))
+        .ok_or_else(|| {
+            candle_core::Error::Msg(format!(
+                "slot of offset {block_offset} in block {block_index} of size {block_size} overflows usize"
+            ))
+        })?;
+    checked_i64(slot, "slot")
+}
+
+/// Converts a position, length or block index to `u32`, erroring if it is
+/// negative or too large rather than silently wrapping.
+pub fn checked_u32<T>(value: T, name: &str) -> Result<u32>
+where
+    T: TryInto<u32> + Copy + Display,
+{
+    value
+        .try_into()
+        .map_err(|_| candle_core::Error::Msg(format!("{name} {value} does not fit in a u32")))
+}
+
+/// Converts a position or slot to `i64`, erroring if it is too large rather
+/// than silently wrapping.
+pub fn checked_i64<T>(value: T, name: &str) -> Result<i64>
+where
+    T: TryInto<i64> + Copy + Display,
+{
+    value
+        .try_into()
+        .map_err(|_| candle_core::Error::Msg(format!("{name} {value} does not fit in an i64")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_positions() -> Result<()> {
+        let block_size = 16;
+        let slot_mapping = [0, 15, 16, -1, 47, 48, -5];
+        let positions = block_positions(&slot_mapping, block_size)?;
+        let position = |block, offset| Some(BlockPosition { block, offset });
+        assert_eq!(
+            positions,
+            [
+                position(0, 0),
+                position(0, 15),
+                position(1, 0),
+                None,
+                position(2, 15),
+                position(3, 0),
+                None,
+            ]
+        );
+        for (slot, position) in slot_mapping
+            .iter()
+            .zip(positions)
+            .filter(|(slot, _)| **slot >= 0)
+        {
+            let BlockPosition { block, offset } = position.unwrap();
+            assert_eq!(slot_for(block as u32, block_size, offset)?, *slot);
+        }
+        assert!(block_positions(&[0], 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blocks_written_last_slot_of_block() -> Result<()> {
+        let block_size = 16;
+        // Slot 47 is the last slot of block 2, slot 48 the first of block 3
+        assert_eq!(blocks_written(&[47], block_size)?, vec![2]);
+        assert_eq!(blocks_written(&[47, 48], block_size)?, vec![2, 3]);
+        assert_eq!(blocks_written(&[-1, 5, 3, -1], block_size)?, vec![0]);
+        assert!(blocks_written(&[0], 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_sequence_slots_partial_last_block() -> Result<()> {
+        let (block_table, sequence_length, block_size) = ([3, 7, 1], 21, 8);
+        // Positions 18..21 sit at offsets 2..5 of the third block, block 1
+        validate_sequence_slots(&[10, 11, 12], &block_table, sequence_length, block_size)?;
+        validate_sequence_slots(
+            &[10, -1, 11, 12, -1],
+            &block_table,
+            sequence_length,
+            block_size,
+        )?;
+        validate_sequence_slots(&[12], &block_table, sequence_length, block_size)?;
+        let prefill = (0..16)
+            .map(|i| [24, 56][i / 8] + (i % 8) as i64)
+            .chain([8, 9, 10, 11, 12])
+            .collect::<Vec<_>>();
+        validate_sequence_slots(&prefill, &block_table, sequence_length, block_size)?;
+        // A table that already holds the next block is valid
+        validate_sequence_slots(&[12], &[3, 7, 1, 5], sequence_length, block_size)?;
+        validate_sequence_slots(&[15], &[3, 7, 1, 5], 24, block_size)?;
+
+        assert!(
+            validate_sequence_slots(&[11, 12, 13], &block_table, sequence_length, block_size)
+                .is_err()
+        );
+        assert!(
+            validate_sequence_slots(&[12], &block_table[..2], sequence_length, block_size).is_err()
+        );
+        assert!(validate_sequence_slots(&prefill, &block_table, 20, block_size).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_checked_conversions_out_of_range() -> Result<()> {
+        assert_eq!(checked_u32(7usize, "position")?, 7);
+        assert!(checked_u32(-1i64, "position").is_err());
+        assert!(checked_u32(u64::from(u32::MAX) + 1, "position").is_err());
+        assert_eq!(checked_i64(7usize, "slot")?, 7);
+        assert!(checked_i64(u64::MAX, "slot").is_err());
+
+        let block_size = 16;
+        let last_block_slot = i64::from(u32::MAX) * block_size as i64;
+        assert_eq!(
+            block_positions(&[last_block_slot], block_size)?,
+            [Some(BlockPosition {
+                block: u32::MAX as usize,
+                offset: 0
+            })]
+        );
+        assert!(block_positions(&[last_block_slot + block_size as i64], block_size).is_err());
+        assert!(slot_for(u32::MAX, usize::MAX, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_padded_block_tables_with_block_zero() -> Result<()> {
+        let (num_blocks, block_size) = (6, 4);
+        // The first sequence really uses block 0 and has pre-allocated block 2
+        // for its next tokens, the second is padded with block 0
+        let block_tables = [vec![0, 5, 2], vec![3]];
+        let sequence_lengths = [7, 3];
+        let padded =
+            padded_block_tables(&block_tables, &sequence_lengths, block_size, &Device::Cpu)?
+                .to_vec2::<u32>()?;
+        assert_eq!(
+            padded,
+            [[0, 5, 2], [3, BLOCK_TABLE_PADDING, BLOCK_TABLE_PADDING]]
+        );
+
+        // Store every token of every sequence in the cache
+        let mut cache = vec![None; num_blocks * block_size];
+        for (sequence, (block_table, sequence_length)) in
+            block_tables.iter().zip(sequence_lengths).enumerate()
+        {
+            for position in 0..sequence_length {
+                let block = block_table[position / block_size] as usize;
+                cache[block * block_size + position % block_size] = Some((sequence, position));
+            }
+        }
+        // Reading each sequence through the padded tables, as attention does,
+        // only ever returns its own tokens
+        for (sequence, (row, sequence_length)) in padded.iter().zip(sequence_lengths).enumerate() {
+            for position in 0..sequence_length {
+                let block = row[position / block_size] as usize;
+                let token = cache[block * block_size + position % block_size];
+                assert_eq!(token, Some((sequence, position)));
+            }
+        }
+        // whereas the padded region of the second sequence holds the first's
+        assert_eq!(
+            cache[BLOCK_TABLE_PADDING as usize * block_size],
+            Some((0, 0))
+        );
+
+        // A table too short for its sequence would read from the padding
+        assert!(padded_block_tables(&[vec![0, 5, 2], vec![3]], &[7, 5], 4, &Device::Cpu).is_err());
+        assert!(padded_block_tables(&block_tables, &[7], 4, &Device::Cpu).is_err());
+        Ok(())
+    }
+}
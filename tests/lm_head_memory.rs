@@ -0,0 +1,86 @@
+//! The chunked lm head reductions must allocate far less than the full
+//! `[num_tokens, vocab_size]` logits of a single matmul. Allocations are
+//! tracked with a counting global allocator, which is why this test lives in
+//! its own binary.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use atoma_paged_attention::lm_head::{chunked_argmax, chunked_topk};
+use candle_core::{DType, Device, Result, Tensor, D};
+
+struct PeakAllocator {
+    allocated: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = self.allocated.fetch_add(layout.size(), Ordering::SeqCst);
+            self.peak
+                .fetch_max(allocated + layout.size(), Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        self.allocated.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator {
+    allocated: AtomicUsize::new(0),
+    peak: AtomicUsize::new(0),
+};
+
+/// Runs `f` and returns its result along with the peak number of bytes it
+/// allocated on top of what was already allocated.
+fn peak_allocated<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let baseline = ALLOCATOR.allocated.load(Ordering::SeqCst);
+    ALLOCATOR.peak.store(baseline, Ordering::SeqCst);
+    let result = f();
+    (result, ALLOCATOR.peak.load(Ordering::SeqCst) - baseline)
+}
+
+#[test]
+fn test_chunked_lm_head_uses_less_peak_memory() -> Result<()> {
+    let device = Device::Cpu;
+    let (num_tokens, hidden_size, vocab_size, chunk_size) = (64, 32, 32_000, 1024);
+    let hidden_states = Tensor::arange(0f32, (num_tokens * hidden_size) as f32, &device)?
+        .affine(0.7, 0.)?
+        .sin()?
+        .reshape((num_tokens, hidden_size))?;
+    let weight = Tensor::arange(0f32, (vocab_size * hidden_size) as f32, &device)?
+        .affine(0.13, 0.)?
+        .cos()?
+        .reshape((vocab_size, hidden_size))?;
+    let logits_bytes = num_tokens * vocab_size * DType::F32.size_in_bytes();
+
+    let (single_shot, single_shot_peak) = peak_allocated(|| {
+        hidden_states
+            .matmul(&weight.t()?)?
+            .argmax(D::Minus1)?
+            .to_vec1::<u32>()
+    });
+    let (chunked, chunked_peak) =
+        peak_allocated(|| chunked_argmax(&hidden_states, &weight, chunk_size));
+    let (topk, topk_peak) = peak_allocated(|| chunked_topk(&hidden_states, &weight, chunk_size, 4));
+
+    assert_eq!(chunked?, single_shot?);
+    let topk = topk?;
+    assert!(topk.iter().all(|row| row.len() == 4));
+    assert!(single_shot_peak >= logits_bytes);
+    for peak in [chunked_peak, topk_peak] {
+        assert!(
+            peak < logits_bytes / 4,
+            "chunked peak of {peak} bytes, single shot logits take {logits_bytes}"
+        );
+    }
+    Ok(())
+}
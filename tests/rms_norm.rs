@@ -0,0 +1,44 @@
+//! candle's fused `rms_norm` accumulates the mean square in f32, even for
+//! bf16 inputs, so bf16 models don't need a dedicated f32 norm.
+
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::ops::rms_norm;
+
+#[test]
+fn test_bf16_rms_norm_matches_f64_reference() -> Result<()> {
+    let device = Device::Cpu;
+    let (num_tokens, hidden_size, eps) = (4, 8192, 1e-5);
+    let xs = Tensor::arange(0f32, (num_tokens * hidden_size) as f32, &device)?
+        .affine(0.37, 0.)?
+        .sin()?
+        .affine(1., 0.5)?
+        .reshape((num_tokens, hidden_size))?
+        .to_dtype(DType::BF16)?;
+    let alpha = Tensor::arange(0f32, hidden_size as f32, &device)?
+        .affine(0.11, 0.)?
+        .cos()?
+        .affine(0.1, 1.)?
+        .to_dtype(DType::BF16)?;
+
+    let normed = rms_norm(&xs, &alpha, eps as f32)?
+        .to_dtype(DType::F64)?
+        .to_vec2::<f64>()?;
+
+    let xs = xs.to_dtype(DType::F64)?.to_vec2::<f64>()?;
+    let alpha = alpha.to_dtype(DType::F64)?.to_vec1::<f64>()?;
+    for (row, normed_row) in xs.iter().zip(normed.iter()) {
+        let mean_square = row.iter().map(|x| x * x).sum::<f64>() / hidden_size as f64;
+        let rms = (mean_square + eps).sqrt();
+        for ((x, alpha), normed) in row.iter().zip(alpha.iter()).zip(normed_row.iter()) {
+            let expected = x / rms * alpha;
+            // The normalized value, its product with alpha and the output are
+            // each rounded to bf16, which stays within 2%. A bf16 accumulator
+            // stalls long before summing 8192 squares and would be far off.
+            assert!(
+                (normed - expected).abs() <= 2e-2 * expected.abs().max(1e-2),
+                "got {normed}, expected {expected}"
+            );
+        }
+    }
+    Ok(())
+}